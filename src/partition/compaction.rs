@@ -0,0 +1,489 @@
+/*
+ * Copyright 2019 Balaji Jinnah and Contributors
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use crate::config::config::Config;
+use crate::partition::posting_list;
+use crate::partition::segment_format::{
+    decode_block, encode_ts_range, ts_range_key, SegmentFooter, SegmentWriter,
+};
+use crate::store::store::Store;
+use crate::types::types::{POSTING_LIST_ALL, SEGMENT_PREFIX};
+use crate::util::decode_u64;
+use failure;
+use failure::bail;
+use fst::{IntoStreamer, Set, SetBuilder, Streamer};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+// SegmentMeta is the minimal information the merge policy needs about a flushed
+// segment to decide whether it should be compacted.
+#[derive(Debug, Clone, Copy)]
+pub struct SegmentMeta {
+    pub id: u64,
+    pub size: u64,
+}
+
+// MergePolicy decides when a set of small segments is worth merging. It triggers
+// once at least `min_segments` segments of at most `max_segment_size` bytes exist,
+// since those are the ones that cost a disproportionate amount of per-query
+// open/seek overhead and FST duplication.
+#[derive(Debug, Clone, Copy)]
+pub struct MergePolicy {
+    pub min_segments: usize,
+    pub max_segment_size: u64,
+}
+
+impl MergePolicy {
+    // pick returns the ids of the segments that should be merged together, or
+    // None when merging isn't worthwhile yet.
+    pub fn pick(&self, segments: &[SegmentMeta]) -> Option<Vec<u64>> {
+        let mut small: Vec<u64> = segments
+            .iter()
+            .filter(|s| s.size <= self.max_segment_size)
+            .map(|s| s.id)
+            .collect();
+        if small.len() < self.min_segments {
+            return None;
+        }
+        // merge oldest first so ids stay monotonically ordered in the merged
+        // posting lists.
+        small.sort();
+        Some(small)
+    }
+}
+
+// MergedSegment is the self contained product of a compaction. The partition
+// manager persists the segment file and fst, and writes every posting list plus
+// the timestamp range sidecar into the store under the merged id, before
+// dropping the source segments.
+pub struct MergedSegment {
+    pub id: u64,
+    pub segment: Vec<u8>,
+    pub fst: Vec<u8>,
+    pub posting_lists: Vec<(String, Vec<u8>)>,
+    // ts_range is the (key, value) sidecar that lets time bounded queries prune
+    // this segment; it must be written alongside the posting lists.
+    pub ts_range: (String, Vec<u8>),
+    pub min_ts: u64,
+    pub max_ts: u64,
+}
+
+// reads the whole logical entry stream of a segment by decompressing every block
+// in order.
+fn read_entry_stream(
+    partition_path: &path::PathBuf,
+    id: u64,
+) -> Result<Vec<u8>, failure::Error> {
+    let mut buffer = Vec::new();
+    File::open(partition_path.join(format!("{}.segment", id)))?.read_to_end(&mut buffer)?;
+    let footer = SegmentFooter::read(&buffer)?;
+    let mut stream = Vec::new();
+    for block in &footer.blocks {
+        let raw = decode_block(&buffer, block.disk_offset, footer.compression)?;
+        stream.extend_from_slice(&raw);
+    }
+    Ok(stream)
+}
+
+// Compactor merges several small segments of a partition into one larger
+// segment, re-basing every posting list offset by the running byte position of
+// its source segment in the merged entry stream.
+pub struct Compactor<'a, S: Store> {
+    config: &'a Config,
+    store: &'a S,
+    partition: String,
+    partition_path: path::PathBuf,
+}
+
+impl<'a, S: Store> Compactor<'a, S> {
+    pub fn new(
+        config: &'a Config,
+        store: &'a S,
+        partition: String,
+        partition_path: path::PathBuf,
+    ) -> Compactor<'a, S> {
+        Compactor {
+            config,
+            store,
+            partition,
+            partition_path,
+        }
+    }
+
+    fn posting_list(&self, id: u64, term: &str) -> Result<Vec<u64>, failure::Error> {
+        let key = format!("{}_{}_{}_{}", SEGMENT_PREFIX, self.partition, id, term);
+        match self.store.get(key.as_bytes())? {
+            Some(list) => posting_list::decode_posting_list(&list),
+            None => bail!("posting list not found for key {}", key),
+        }
+    }
+
+    // compact merges `source_ids` (in ascending order) into a single segment
+    // with id `new_id`.
+    pub fn compact(
+        &self,
+        source_ids: &[u64],
+        new_id: u64,
+    ) -> Result<MergedSegment, failure::Error> {
+        let compression = self.config.segment_compression();
+        let mut writer = SegmentWriter::new(compression, self.config.segment_block_size());
+        // terms map to the merged, re-based posting list; a BTreeMap keeps the
+        // terms sorted for fst insertion.
+        let mut terms: BTreeMap<String, Vec<u64>> = BTreeMap::new();
+        let mut all: Vec<u64> = Vec::new();
+        let mut min_ts = u64::max_value();
+        let mut max_ts = 0u64;
+        // running byte position of the next source segment in the merged stream.
+        let mut base: u64 = 0;
+
+        for &id in source_ids {
+            let stream = read_entry_stream(&self.partition_path, id)?;
+            // re-frame each entry into the merged writer and track timestamps.
+            let mut offset = 0usize;
+            while offset < stream.len() {
+                let entry_len = decode_u64(&stream[offset..offset + 8]) as usize;
+                let frame = &stream[offset..offset + 8 + entry_len];
+                let ts = decode_u64(&stream[offset + 8..offset + 16]);
+                min_ts = std::cmp::min(min_ts, ts);
+                max_ts = std::cmp::max(max_ts, ts);
+                writer.append(frame);
+                offset += 8 + entry_len;
+            }
+
+            // re-base every term's posting list by `base`.
+            let index_set = unsafe {
+                Set::from_path(
+                    self.partition_path
+                        .join(format!("segment_index_{}.fst", id)),
+                )
+            }?;
+            let mut index_stream = index_set.into_stream();
+            while let Some(term_bytes) = index_stream.next() {
+                let term = String::from_utf8(term_bytes.to_vec())?;
+                let list = self.posting_list(id, &term)?;
+                let rebased = terms.entry(term).or_insert_with(Vec::new);
+                for off in list {
+                    rebased.push(off + base);
+                }
+            }
+
+            // re-base and accumulate the ALL list, deduplicating it at the end.
+            for off in self.posting_list(id, POSTING_LIST_ALL)? {
+                all.push(off + base);
+            }
+
+            base += stream.len() as u64;
+        }
+
+        // build the merged fst and the posting lists to persist.
+        let mut fst_builder = SetBuilder::memory();
+        let mut posting_lists = Vec::with_capacity(terms.len() + 1);
+        for (term, mut offsets) in terms {
+            offsets.sort();
+            offsets.dedup();
+            fst_builder.insert(&term)?;
+            let key = format!("{}_{}_{}_{}", SEGMENT_PREFIX, self.partition, new_id, term);
+            posting_lists.push((key, posting_list::encode_posting_list(&offsets)));
+        }
+        all.sort();
+        all.dedup();
+        let all_key = format!(
+            "{}_{}_{}_{}",
+            SEGMENT_PREFIX, self.partition, new_id, POSTING_LIST_ALL
+        );
+        posting_lists.push((all_key, posting_list::encode_posting_list(&all)));
+
+        let fst = fst_builder.into_inner()?;
+        if min_ts == u64::max_value() {
+            min_ts = 0;
+        }
+        // the timestamp range sidecar so time bounded queries can prune the
+        // merged segment without opening it.
+        let ts_range = (
+            ts_range_key(&self.partition, new_id),
+            encode_ts_range(min_ts, max_ts),
+        );
+        Ok(MergedSegment {
+            id: new_id,
+            segment: writer.finish(),
+            fst,
+            posting_lists,
+            ts_range,
+            min_ts,
+            max_ts,
+        })
+    }
+}
+
+// SegmentCatalog is the partition manager's side of compaction: it lists what's
+// on disk, hands out ids and commits the merged artifact. The background
+// compactor drives it; keeping it a trait means the manager owns persistence and
+// segment swapping.
+pub trait SegmentCatalog {
+    // partitions worth considering for compaction.
+    fn partitions(&self) -> Vec<String>;
+    // segments currently present for a partition.
+    fn segments(&self, partition: &str) -> Result<Vec<SegmentMeta>, failure::Error>;
+    // directory holding a partition's `.segment`/`.fst` files.
+    fn partition_path(&self, partition: &str) -> path::PathBuf;
+    // id to give the merged segment.
+    fn next_segment_id(&self, partition: &str) -> Result<u64, failure::Error>;
+    // persist the merged segment and drop the now merged sources.
+    fn commit_merge(
+        &self,
+        partition: &str,
+        merged: MergedSegment,
+        sources: &[u64],
+    ) -> Result<(), failure::Error>;
+}
+
+// BackgroundCompactor runs compaction on its own thread, periodically scanning
+// every partition and merging the small segments the policy selects.
+pub struct BackgroundCompactor<C, S> {
+    catalog: Arc<C>,
+    store: Arc<S>,
+    config: Arc<Config>,
+    policy: MergePolicy,
+    interval: Duration,
+}
+
+// CompactionHandle keeps the worker alive and lets the owner stop it.
+pub struct CompactionHandle {
+    stop: Arc<AtomicBool>,
+    join: Option<thread::JoinHandle<()>>,
+}
+
+impl CompactionHandle {
+    // shutdown signals the worker and waits for it to finish the current cycle.
+    pub fn shutdown(mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(join) = self.join.take() {
+            let _ = join.join();
+        }
+    }
+}
+
+impl<C, S> BackgroundCompactor<C, S>
+where
+    C: SegmentCatalog + Send + Sync + 'static,
+    S: Store + Send + Sync + 'static,
+{
+    pub fn new(
+        catalog: Arc<C>,
+        store: Arc<S>,
+        config: Arc<Config>,
+        policy: MergePolicy,
+        interval: Duration,
+    ) -> BackgroundCompactor<C, S> {
+        BackgroundCompactor {
+            catalog,
+            store,
+            config,
+            policy,
+            interval,
+        }
+    }
+
+    // start spawns the worker thread and returns a handle to stop it.
+    pub fn start(self) -> CompactionHandle {
+        let stop = Arc::new(AtomicBool::new(false));
+        let worker_stop = stop.clone();
+        let join = thread::spawn(move || {
+            while !worker_stop.load(Ordering::SeqCst) {
+                if let Err(err) =
+                    run_once(&*self.catalog, &*self.store, &self.config, &self.policy)
+                {
+                    // a bad segment shouldn't kill the loop; log and retry later.
+                    eprintln!("compaction cycle failed: {}", err);
+                }
+                thread::sleep(self.interval);
+            }
+        });
+        CompactionHandle {
+            stop,
+            join: Some(join),
+        }
+    }
+}
+
+// run_once scans every partition and compacts the segments the policy selects.
+fn run_once<C: SegmentCatalog, S: Store>(
+    catalog: &C,
+    store: &S,
+    config: &Config,
+    policy: &MergePolicy,
+) -> Result<(), failure::Error> {
+    for partition in catalog.partitions() {
+        let segments = catalog.segments(&partition)?;
+        let sources = match policy.pick(&segments) {
+            Some(ids) => ids,
+            None => continue,
+        };
+        let new_id = catalog.next_segment_id(&partition)?;
+        let merged = {
+            let compactor = Compactor::new(
+                config,
+                store,
+                partition.clone(),
+                catalog.partition_path(&partition),
+            );
+            compactor.compact(&sources, new_id)?
+        };
+        catalog.commit_merge(&partition, merged, &sources)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::partition::segment_format::CompressionType;
+    use std::collections::HashMap;
+    use std::sync::atomic::AtomicUsize;
+
+    #[derive(Default)]
+    struct MemStore {
+        map: HashMap<Vec<u8>, Vec<u8>>,
+    }
+
+    impl Store for MemStore {
+        fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, failure::Error> {
+            Ok(self.map.get(key).cloned())
+        }
+    }
+
+    fn tmpdir() -> path::PathBuf {
+        static N: AtomicUsize = AtomicUsize::new(0);
+        let mut dir = std::env::temp_dir();
+        dir.push(format!(
+            "pathivu-compaction-{}-{}",
+            std::process::id(),
+            N.fetch_add(1, Ordering::SeqCst)
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn frame(ts: u64, line: &str) -> Vec<u8> {
+        let mut f = Vec::new();
+        f.extend_from_slice(&((8 + line.len()) as u64).to_be_bytes());
+        f.extend_from_slice(&ts.to_be_bytes());
+        f.extend_from_slice(line.as_bytes());
+        f
+    }
+
+    // write a segment, its fst and posting lists; return the entry stream length.
+    fn write_segment(
+        dir: &path::PathBuf,
+        store: &mut MemStore,
+        partition: &str,
+        id: u64,
+        entries: &[(u64, &str)],
+    ) -> u64 {
+        let mut writer = SegmentWriter::new(CompressionType::None, 1 << 20);
+        let mut terms: BTreeMap<String, Vec<u64>> = BTreeMap::new();
+        let mut all = Vec::new();
+        let mut off = 0u64;
+        for (ts, line) in entries {
+            let f = frame(*ts, line);
+            writer.append(&f);
+            terms
+                .entry(line.to_string())
+                .or_insert_with(Vec::new)
+                .push(off);
+            all.push(off);
+            off += f.len() as u64;
+        }
+        std::fs::write(dir.join(format!("{}.segment", id)), writer.finish()).unwrap();
+        let mut builder = SetBuilder::memory();
+        for (term, offs) in &terms {
+            builder.insert(term).unwrap();
+            let key = format!("{}_{}_{}_{}", SEGMENT_PREFIX, partition, id, term);
+            store
+                .map
+                .insert(key.into_bytes(), posting_list::encode_posting_list(offs));
+        }
+        std::fs::write(
+            dir.join(format!("segment_index_{}.fst", id)),
+            builder.into_inner().unwrap(),
+        )
+        .unwrap();
+        let all_key = format!("{}_{}_{}_{}", SEGMENT_PREFIX, partition, id, POSTING_LIST_ALL);
+        store
+            .map
+            .insert(all_key.into_bytes(), posting_list::encode_posting_list(&all));
+        off
+    }
+
+    fn find<'a>(lists: &'a [(String, Vec<u8>)], suffix: &str) -> &'a [u8] {
+        lists
+            .iter()
+            .find(|(k, _)| k.ends_with(suffix))
+            .map(|(_, v)| v.as_slice())
+            .unwrap()
+    }
+
+    #[test]
+    fn merge_policy_filters_by_count_and_size() {
+        let policy = MergePolicy {
+            min_segments: 2,
+            max_segment_size: 100,
+        };
+        // too few small segments.
+        assert_eq!(policy.pick(&[SegmentMeta { id: 1, size: 10 }]), None);
+        // large segments don't count towards the threshold.
+        let segs = [
+            SegmentMeta { id: 1, size: 10 },
+            SegmentMeta { id: 2, size: 500 },
+        ];
+        assert_eq!(policy.pick(&segs), None);
+        // enough small ones, returned oldest first.
+        let segs = [
+            SegmentMeta { id: 3, size: 10 },
+            SegmentMeta { id: 1, size: 20 },
+            SegmentMeta { id: 9, size: 500 },
+        ];
+        assert_eq!(policy.pick(&segs), Some(vec![1, 3]));
+    }
+
+    #[test]
+    fn compact_rebases_offsets_and_dedups_all() {
+        let dir = tmpdir();
+        let partition = "p";
+        let mut store = MemStore::default();
+        let len0 = write_segment(&dir, &mut store, partition, 0, &[(10, "a"), (20, "b")]);
+        write_segment(&dir, &mut store, partition, 1, &[(30, "a"), (40, "c")]);
+
+        let config = Config::default();
+        let compactor = Compactor::new(&config, &store, partition.to_string(), dir.clone());
+        let merged = compactor.compact(&[0, 1], 2).unwrap();
+
+        // "a" is in both segments; the second occurrence is re-based by the byte
+        // length of the first segment's stream.
+        let a = posting_list::decode_posting_list(find(&merged.posting_lists, "_2_a")).unwrap();
+        assert_eq!(a, vec![0, len0]);
+        // the ALL list is the re-based union, sorted and de-duplicated.
+        let all =
+            posting_list::decode_posting_list(find(&merged.posting_lists, POSTING_LIST_ALL)).unwrap();
+        assert_eq!(all, vec![0, 17, len0, len0 + 17]);
+        assert_eq!((merged.min_ts, merged.max_ts), (10, 40));
+    }
+}
@@ -0,0 +1,110 @@
+/*
+ * Copyright 2019 Balaji Jinnah and Contributors
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use crate::util::decode_u64;
+use failure;
+use failure::bail;
+
+// Posting list formats. Every list written by the current code starts with a
+// version byte. Legacy lists predate the version byte and are a bare array of
+// big endian u64 offsets; they are detected by the absence of a known version
+// tag in the leading byte (file offsets are small, so their leading byte is
+// never VERSION_DELTA_VARINT).
+const VERSION_DELTA_VARINT: u8 = 1;
+
+// encode_posting_list encodes a list of monotonically increasing offsets as a
+// version byte followed by delta + LEB128 varints: the first offset is stored
+// absolute and each subsequent offset as the gap from its predecessor. For the
+// dense, mostly sequential offsets of real logs this is several times smaller
+// than fixed width u64s.
+pub fn encode_posting_list(offsets: &[u64]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(1 + offsets.len());
+    buf.push(VERSION_DELTA_VARINT);
+    let mut prev = 0u64;
+    for (i, &offset) in offsets.iter().enumerate() {
+        let delta = if i == 0 { offset } else { offset - prev };
+        write_varint(&mut buf, delta);
+        prev = offset;
+    }
+    buf
+}
+
+// decode_posting_list decodes both the delta + varint format and the legacy
+// fixed width format. The returned offsets are in ascending order, which the
+// varint format preserves for free via the running sum.
+pub fn decode_posting_list(buf: &[u8]) -> Result<Vec<u64>, failure::Error> {
+    if buf.is_empty() {
+        return Ok(Vec::new());
+    }
+    if buf[0] == VERSION_DELTA_VARINT {
+        return decode_delta_varint(&buf[1..]);
+    }
+    // legacy: a packed array of big endian u64s.
+    if buf.len() % 8 != 0 {
+        bail!("corrupt fixed width posting list of length {}", buf.len());
+    }
+    let mut offsets = Vec::with_capacity(buf.len() / 8);
+    let mut cursor = 0;
+    while cursor < buf.len() {
+        offsets.push(decode_u64(&buf[cursor..cursor + 8]));
+        cursor += 8;
+    }
+    Ok(offsets)
+}
+
+fn decode_delta_varint(buf: &[u8]) -> Result<Vec<u64>, failure::Error> {
+    let mut offsets = Vec::new();
+    let mut cursor = 0;
+    let mut running = 0u64;
+    while cursor < buf.len() {
+        let (delta, read) = read_varint(&buf[cursor..])?;
+        running += delta;
+        offsets.push(running);
+        cursor += read;
+    }
+    Ok(offsets)
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let mut byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value != 0 {
+            byte |= 0x80;
+            buf.push(byte);
+        } else {
+            buf.push(byte);
+            break;
+        }
+    }
+}
+
+// read_varint decodes one LEB128 value and returns it along with the number of
+// bytes consumed.
+fn read_varint(buf: &[u8]) -> Result<(u64, usize), failure::Error> {
+    let mut value = 0u64;
+    let mut shift = 0;
+    for (i, &byte) in buf.iter().enumerate() {
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok((value, i + 1));
+        }
+        shift += 7;
+        if shift >= 64 {
+            bail!("varint overflows u64");
+        }
+    }
+    bail!("truncated varint in posting list");
+}
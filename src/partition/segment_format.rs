@@ -0,0 +1,308 @@
+/*
+ * Copyright 2019 Balaji Jinnah and Contributors
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use crate::types::types::SEGMENT_PREFIX;
+use crate::util::decode_u64;
+use failure;
+use failure::bail;
+use xxhash_rust::xxh3::xxh3_64;
+
+// store key suffix for a segment's min/max timestamp sidecar.
+pub const TS_RANGE_SUFFIX: &str = "tsrange";
+
+// sidecar key for a segment's timestamp range.
+pub fn ts_range_key(partition: &str, id: u64) -> String {
+    format!("{}_{}_{}_{}", SEGMENT_PREFIX, partition, id, TS_RANGE_SUFFIX)
+}
+
+// encode a [min_ts, max_ts] pair.
+pub fn encode_ts_range(min_ts: u64, max_ts: u64) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(16);
+    buf.extend_from_slice(&min_ts.to_be_bytes());
+    buf.extend_from_slice(&max_ts.to_be_bytes());
+    buf
+}
+
+// decode a [min_ts, max_ts] pair.
+pub fn decode_ts_range(buf: &[u8]) -> Result<(u64, u64), failure::Error> {
+    if buf.len() != 16 {
+        bail!("corrupt timestamp range sidecar of length {}", buf.len());
+    }
+    Ok((decode_u64(&buf[..8]), decode_u64(&buf[8..16])))
+}
+
+// On-disk layout:
+//   [block 0]...[block N][footer][footer_len: u64 BE]
+//   block = [uncompressed_len: u64][xxh3: u64][compressed_len: u64][compressed bytes]
+// Blocks break only between entries, so every offset lands in one block.
+
+// CompressionType selects the per block codec.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompressionType {
+    None,
+    Lz4,
+    Deflate,
+}
+
+impl CompressionType {
+    pub fn to_u8(&self) -> u8 {
+        match self {
+            CompressionType::None => 0,
+            CompressionType::Lz4 => 1,
+            CompressionType::Deflate => 2,
+        }
+    }
+
+    pub fn from_u8(byte: u8) -> Result<CompressionType, failure::Error> {
+        match byte {
+            0 => Ok(CompressionType::None),
+            1 => Ok(CompressionType::Lz4),
+            2 => Ok(CompressionType::Deflate),
+            _ => bail!("unknown compression type {}", byte),
+        }
+    }
+
+    pub fn compress(&self, raw: &[u8]) -> Vec<u8> {
+        match self {
+            CompressionType::None => raw.to_vec(),
+            CompressionType::Lz4 => lz4_flex::compress(raw),
+            CompressionType::Deflate => {
+                miniz_oxide::deflate::compress_to_vec(raw, 6)
+            }
+        }
+    }
+
+    pub fn decompress(
+        &self,
+        data: &[u8],
+        uncompressed_len: usize,
+    ) -> Result<Vec<u8>, failure::Error> {
+        match self {
+            CompressionType::None => Ok(data.to_vec()),
+            CompressionType::Lz4 => lz4_flex::decompress(data, uncompressed_len)
+                .map_err(|e| failure::format_err!("lz4 decompress: {}", e)),
+            CompressionType::Deflate => {
+                miniz_oxide::inflate::decompress_to_vec(data)
+                    .map_err(|e| failure::format_err!("deflate decompress: {:?}", e))
+            }
+        }
+    }
+}
+
+// BlockMeta locates a block on disk and in the logical entry stream.
+#[derive(Debug, Clone, Copy)]
+pub struct BlockMeta {
+    // start of the block frame on disk.
+    pub disk_offset: u64,
+    // offset of the block's first entry in the uncompressed stream.
+    pub logical_offset: u64,
+    pub uncompressed_len: u64,
+}
+
+// SegmentFooter is the trailer that makes block lookup possible.
+#[derive(Debug)]
+pub struct SegmentFooter {
+    pub compression: CompressionType,
+    pub blocks: Vec<BlockMeta>,
+}
+
+impl SegmentFooter {
+    // block_for_offset returns the index of the block holding `logical_offset`.
+    pub fn block_for_offset(&self, logical_offset: u64) -> Option<usize> {
+        // blocks are emitted in logical order, so a simple binary search finds
+        // the last block whose start is <= the offset.
+        let idx = match self
+            .blocks
+            .binary_search_by(|b| b.logical_offset.cmp(&logical_offset))
+        {
+            Ok(i) => i,
+            Err(0) => return None,
+            Err(i) => i - 1,
+        };
+        let block = &self.blocks[idx];
+        if logical_offset < block.logical_offset + block.uncompressed_len {
+            Some(idx)
+        } else {
+            None
+        }
+    }
+
+    // serialize encodes the footer into its on-disk representation including the
+    // trailing footer length.
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.push(self.compression.to_u8());
+        buf.extend_from_slice(&(self.blocks.len() as u64).to_be_bytes());
+        for block in &self.blocks {
+            buf.extend_from_slice(&block.disk_offset.to_be_bytes());
+            buf.extend_from_slice(&block.logical_offset.to_be_bytes());
+            buf.extend_from_slice(&block.uncompressed_len.to_be_bytes());
+        }
+        let footer_len = buf.len() as u64;
+        buf.extend_from_slice(&footer_len.to_be_bytes());
+        buf
+    }
+
+    // read parses the footer from the tail of a whole segment buffer.
+    pub fn read(buffer: &[u8]) -> Result<SegmentFooter, failure::Error> {
+        if buffer.len() < 8 {
+            bail!("segment too small to contain a footer");
+        }
+        let footer_len = decode_u64(&buffer[buffer.len() - 8..]) as usize;
+        if footer_len + 8 > buffer.len() {
+            bail!("corrupt segment footer length {}", footer_len);
+        }
+        let start = buffer.len() - 8 - footer_len;
+        let footer = &buffer[start..buffer.len() - 8];
+        // the footer header is a compression byte plus an 8 byte block count.
+        if footer.len() < 9 {
+            bail!("truncated segment footer of length {}", footer.len());
+        }
+        let compression = CompressionType::from_u8(footer[0])?;
+        let block_count = decode_u64(&footer[1..9]) as usize;
+        // the block count is corruption controlled, so validate it against the
+        // actual footer length before allocating or slicing per block.
+        let expected = block_count
+            .checked_mul(24)
+            .and_then(|n| n.checked_add(9));
+        if expected != Some(footer.len()) {
+            bail!(
+                "corrupt segment footer: {} blocks do not fit in {} bytes",
+                block_count,
+                footer.len()
+            );
+        }
+        let mut blocks = Vec::with_capacity(block_count);
+        let mut cursor = 9;
+        for _ in 0..block_count {
+            let disk_offset = decode_u64(&footer[cursor..cursor + 8]);
+            let logical_offset = decode_u64(&footer[cursor + 8..cursor + 16]);
+            let uncompressed_len = decode_u64(&footer[cursor + 16..cursor + 24]);
+            blocks.push(BlockMeta {
+                disk_offset,
+                logical_offset,
+                uncompressed_len,
+            });
+            cursor += 24;
+        }
+        Ok(SegmentFooter {
+            compression,
+            blocks,
+        })
+    }
+}
+
+// decode_block reads, checksum verifies and decompresses a single block frame at
+// `disk_offset`. It bails on a checksum mismatch rather than handing back
+// corrupt bytes.
+pub fn decode_block(
+    buffer: &[u8],
+    disk_offset: u64,
+    compression: CompressionType,
+) -> Result<Vec<u8>, failure::Error> {
+    let header = disk_offset as usize;
+    if header + 24 > buffer.len() {
+        bail!("truncated block header at offset {}", disk_offset);
+    }
+    let uncompressed_len = decode_u64(&buffer[header..header + 8]) as usize;
+    let checksum = decode_u64(&buffer[header + 8..header + 16]);
+    let compressed_len = decode_u64(&buffer[header + 16..header + 24]) as usize;
+    let data_start = header + 24;
+    if data_start + compressed_len > buffer.len() {
+        bail!("truncated block body at offset {}", disk_offset);
+    }
+    let raw = compression.decompress(
+        &buffer[data_start..data_start + compressed_len],
+        uncompressed_len,
+    )?;
+    if raw.len() != uncompressed_len {
+        bail!(
+            "block at offset {} decompressed to {} bytes, expected {}",
+            disk_offset,
+            raw.len(),
+            uncompressed_len
+        );
+    }
+    if xxh3_64(&raw) != checksum {
+        bail!("checksum mismatch for block at offset {}", disk_offset);
+    }
+    Ok(raw)
+}
+
+// SegmentWriter accumulates length prefixed entries, flushing them into
+// independently compressed blocks once a block reaches `block_size`.
+pub struct SegmentWriter {
+    compression: CompressionType,
+    block_size: usize,
+    out: Vec<u8>,
+    current: Vec<u8>,
+    blocks: Vec<BlockMeta>,
+    logical_offset: u64,
+}
+
+impl SegmentWriter {
+    pub fn new(compression: CompressionType, block_size: usize) -> SegmentWriter {
+        SegmentWriter {
+            compression,
+            block_size,
+            out: Vec::new(),
+            current: Vec::new(),
+            blocks: Vec::new(),
+            logical_offset: 0,
+        }
+    }
+
+    // append adds one already framed entry (its 8 byte length prefix plus body).
+    // Blocks are only sealed between entries so an entry never straddles a block
+    // boundary.
+    pub fn append(&mut self, entry: &[u8]) {
+        if !self.current.is_empty() && self.current.len() + entry.len() > self.block_size {
+            self.flush_block();
+        }
+        self.current.extend_from_slice(entry);
+    }
+
+    fn flush_block(&mut self) {
+        if self.current.is_empty() {
+            return;
+        }
+        let raw = std::mem::take(&mut self.current);
+        let disk_offset = self.out.len() as u64;
+        let compressed = self.compression.compress(&raw);
+        self.out.extend_from_slice(&(raw.len() as u64).to_be_bytes());
+        self.out.extend_from_slice(&xxh3_64(&raw).to_be_bytes());
+        self.out
+            .extend_from_slice(&(compressed.len() as u64).to_be_bytes());
+        self.out.extend_from_slice(&compressed);
+        self.blocks.push(BlockMeta {
+            disk_offset,
+            logical_offset: self.logical_offset,
+            uncompressed_len: raw.len() as u64,
+        });
+        self.logical_offset += raw.len() as u64;
+    }
+
+    // finish seals the final block, appends the footer and returns the complete
+    // segment bytes ready to be written to disk.
+    pub fn finish(mut self) -> Vec<u8> {
+        self.flush_block();
+        let footer = SegmentFooter {
+            compression: self.compression,
+            blocks: std::mem::take(&mut self.blocks),
+        };
+        self.out.extend_from_slice(&footer.serialize());
+        self.out
+    }
+}
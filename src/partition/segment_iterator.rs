@@ -16,6 +16,7 @@
 use crate::config::config::Config;
 use crate::partition::iterator::Iterator;
 use crate::partition::posting_list;
+use crate::partition::segment_format::{decode_block, decode_ts_range, ts_range_key, SegmentFooter};
 use crate::store::batch::Batch;
 use crate::store::store::Store;
 use crate::types::types::{POSTING_LIST_ALL, SEGMENT_PREFIX};
@@ -24,9 +25,10 @@ use failure;
 use failure::bail;
 use fst::{IntoStreamer, Set, Streamer};
 use fst_levenshtein::Levenshtein;
+use fst_regex::Regex;
+use memmap::Mmap;
 use std::collections::HashSet;
 use std::fs::File;
-use std::io::Read;
 use std::marker::PhantomData;
 use std::path;
 use std::rc::Rc;
@@ -37,6 +39,51 @@ pub struct Entry {
     pub ts: u64,
 }
 
+// default fuzzy edit distance.
+const DEFAULT_FUZZY_DISTANCE: u32 = 2;
+
+// QueryMode decides how a query is matched against the index.
+#[derive(Debug, PartialEq)]
+pub enum QueryMode {
+    Exact(String),
+    Prefix(String),
+    Fuzzy { term: String, distance: u32 },
+    Regex(String),
+}
+
+impl QueryMode {
+    // parse the query syntax: =term exact, term* prefix, /expr/ regex, else fuzzy.
+    pub fn from_query(query: &str) -> QueryMode {
+        if query.len() >= 2 && query.starts_with('/') && query.ends_with('/') {
+            return QueryMode::Regex(query[1..query.len() - 1].to_string());
+        }
+        if let Some(term) = query.strip_prefix('=') {
+            return QueryMode::Exact(term.to_string());
+        }
+        if let Some(term) = query.strip_suffix('*') {
+            return QueryMode::Prefix(term.to_string());
+        }
+        QueryMode::Fuzzy {
+            term: query.to_string(),
+            distance: DEFAULT_FUZZY_DISTANCE,
+        }
+    }
+}
+
+// least key greater than every key with `prefix`, or None if unbounded above.
+fn prefix_successor(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut successor = prefix.to_vec();
+    while let Some(last) = successor.last().cloned() {
+        if last == 0xff {
+            successor.pop();
+        } else {
+            *successor.last_mut().unwrap() = last + 1;
+            return Some(successor);
+        }
+    }
+    None
+}
+
 // SegmentIterator is used to iterate over segment files.
 pub struct SegmentIterator<S> {
     store: S,
@@ -57,8 +104,29 @@ impl<S: Store> SegmentIterator<S> {
         start_ts: u64,
         end_ts: u64,
     ) -> Result<SegmentIterator<S>, failure::Error> {
+        // prune via the sidecar if the window is disjoint, before opening files.
+        // a (0, 0) window means unbounded.
+        if !(start_ts == 0 && end_ts == 0) {
+            if let Some(raw) = store.get(ts_range_key(&partition, id).as_bytes())? {
+                let (min_ts, max_ts) = decode_ts_range(&raw)?;
+                if end_ts < min_ts || start_ts > max_ts {
+                    return Ok(SegmentIterator {
+                        store: store,
+                        entries: Vec::new(),
+                        id: id,
+                        current_index: 0,
+                        nothing_track: true,
+                        partition: partition,
+                    });
+                }
+            }
+        }
         // let collect all the posting list for the given indices.
         let mut entry_indices = Vec::new();
+        // number of posting lists appended; a single list is already sorted by
+        // construction (the delta + varint decode preserves ascending order) so
+        // we can skip the re-sort below.
+        let mut list_count = 0usize;
 
         // query fst if there is any query string.
         if query != "" {
@@ -69,10 +137,36 @@ impl<S: Store> SegmentIterator<S> {
                 Set::from_path(partition_path.join(format!("segment_index_{}.fst", id)))
             }?;
 
-            let fuzzy_query = Levenshtein::new(&query, 2)?;
-            let indices_stream = index_set.search(fuzzy_query).into_stream().into_strs()?;
+            // only the automaton differs per mode; matches feed the same loop below.
+            let mut matched_indices: Vec<String> = Vec::new();
+            match QueryMode::from_query(&query) {
+                QueryMode::Exact(term) => {
+                    // a single membership check, no automaton needed.
+                    if index_set.contains(&term) {
+                        matched_indices.push(term);
+                    }
+                }
+                QueryMode::Prefix(term) => {
+                    // stream the range [term, successor(term)).
+                    let mut stream = match prefix_successor(term.as_bytes()) {
+                        Some(upper) => index_set.range().ge(&term).lt(upper).into_stream(),
+                        None => index_set.range().ge(&term).into_stream(),
+                    };
+                    while let Some(key) = stream.next() {
+                        matched_indices.push(String::from_utf8(key.to_vec())?);
+                    }
+                }
+                QueryMode::Fuzzy { term, distance } => {
+                    let fuzzy_query = Levenshtein::new(&term, distance)?;
+                    matched_indices = index_set.search(fuzzy_query).into_stream().into_strs()?;
+                }
+                QueryMode::Regex(pattern) => {
+                    let regex_query = Regex::new(&pattern)?;
+                    matched_indices = index_set.search(regex_query).into_stream().into_strs()?;
+                }
+            }
             // get all the posting list for the given indices.
-            for index in indices_stream {
+            for index in matched_indices {
                 // TODO: Don't use get here. Change this to prefix iterator. based on the key do the
                 // decoding stuff.
                 let index_key = format!("{}_{}_{}_{}", SEGMENT_PREFIX, partition, id, &index);
@@ -80,10 +174,11 @@ impl<S: Store> SegmentIterator<S> {
                 // skip if there is no value. Ideally we should thrown an error. Because index is
                 // missing
                 if list.is_none() {
-                    panic!("posting list not found for the index  key {}", index_key);
+                    bail!("posting list not found for the index  key {}", index_key);
                 }
                 let mut list = posting_list::decode_posting_list(&list.unwrap())?;
                 entry_indices.append(&mut list);
+                list_count += 1;
             }
         } else {
             let index_key = format!(
@@ -94,37 +189,61 @@ impl<S: Store> SegmentIterator<S> {
             // skip if there is no value. Ideally we should thrown an error. Because index is
             // missing
             if list.is_none() {
-                panic!("posting list not found for the index  key {}", index_key);
+                bail!("posting list not found for the index  key {}", index_key);
             }
             let mut list = posting_list::decode_posting_list(&list.unwrap())?;
             entry_indices.append(&mut list);
+            list_count += 1;
         }
         // now sort all the indices. because there may be duplication and ordering.
         // But the indices in the posting list are in sorted order. Here we can make one
         // more micro optimization if there is only one set no need to sort.
-        entry_indices.sort();
-        // open segment file.
-        let mut segment_file = File::open(partition_path.join(format!("{}.segment", id)))?;
-        // we'll read all since random access is expensive.
-        let mut buffer = Vec::new();
-        segment_file.read_to_end(&mut buffer)?;
+        if list_count > 1 {
+            entry_indices.sort();
+        }
+        // map the segment once; only touched pages fault in.
+        let segment_file = File::open(partition_path.join(format!("{}.segment", id)))?;
+        let mmap = unsafe { Mmap::map(&segment_file)? };
+        let buffer: &[u8] = &mmap;
+        let footer = SegmentFooter::read(buffer)?;
+        let mut block_cache: std::collections::HashMap<usize, Vec<u8>> =
+            std::collections::HashMap::new();
         // There may be lot of duplicate element so we should avoid that.
         let mut seen_set = HashSet::new();
         let mut entries = Vec::new();
         for line_offset in entry_indices {
-            let mut read_offset = line_offset as usize;
+            let read_offset = line_offset as usize;
             match seen_set.get(&read_offset) {
                 Some(_) => {
                     // We already seen this offset so skipping this offset.
                     continue;
                 }
                 None => {
-                    // Now we have to decode the entry.
+                    // decompress the block holding this offset, lazily.
+                    let block_idx = match footer.block_for_offset(line_offset) {
+                        Some(idx) => idx,
+                        None => bail!("posting list offset {} is out of range", line_offset),
+                    };
+                    let block = footer.blocks[block_idx];
+                    if !block_cache.contains_key(&block_idx) {
+                        let raw = decode_block(buffer, block.disk_offset, footer.compression)?;
+                        block_cache.insert(block_idx, raw);
+                    }
+                    let raw = &block_cache[&block_idx];
+                    // shift the logical offset into the decompressed block.
+                    let mut local = (line_offset - block.logical_offset) as usize;
+                    // a stale or corrupt offset may point past the real entries,
+                    // so bound every slice instead of panicking.
+                    if local + 8 > raw.len() {
+                        bail!("entry offset {} past end of block", line_offset);
+                    }
                     // 8 bytes from this offset gives the length of the entry.
-                    let entry_len = decode_u64(&buffer[read_offset..read_offset + 8]) as usize;
-                    // advancing read offset.
-                    read_offset = read_offset + 8;
-                    let entry = decode_entry(&buffer[read_offset..read_offset + entry_len]);
+                    let entry_len = decode_u64(&raw[local..local + 8]) as usize;
+                    local = local + 8;
+                    if entry_len < 8 || local + entry_len > raw.len() {
+                        bail!("entry at offset {} overruns block", line_offset);
+                    }
+                    let entry = decode_entry(&raw[local..local + entry_len]);
                     if (start_ts <= entry.ts && entry.ts <= end_ts)
                         || (start_ts == 0 && end_ts == 0)
                     {
@@ -157,7 +276,9 @@ impl<S: Store> Iterator for SegmentIterator<S> {
 
     /// next will advance the iterator. throws error if we reach end.
     fn next(&mut self) -> Option<()> {
-        if self.current_index >= self.entries.len() - 1 {
+        // guard against the empty segment case; `entries.len() - 1` would
+        // underflow when there are no entries at all.
+        if self.current_index + 1 >= self.entries.len() {
             // just incrementing one so that entry will give none.
             self.current_index = self.current_index + 1;
             return None;
@@ -174,3 +295,195 @@ pub fn decode_entry(line_buf: &[u8]) -> Entry {
         line: line_buf[8..].to_vec(),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::partition::segment_format::{CompressionType, SegmentWriter};
+    use fst::SetBuilder;
+    use std::collections::BTreeMap;
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Default)]
+    struct MemStore {
+        map: HashMap<Vec<u8>, Vec<u8>>,
+    }
+
+    impl Store for MemStore {
+        fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, failure::Error> {
+            Ok(self.map.get(key).cloned())
+        }
+    }
+
+    fn tmpdir() -> path::PathBuf {
+        static N: AtomicUsize = AtomicUsize::new(0);
+        let mut dir = std::env::temp_dir();
+        dir.push(format!(
+            "pathivu-segiter-{}-{}",
+            std::process::id(),
+            N.fetch_add(1, Ordering::SeqCst)
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn frame(ts: u64, line: &str) -> Vec<u8> {
+        let mut f = Vec::new();
+        f.extend_from_slice(&((8 + line.len()) as u64).to_be_bytes());
+        f.extend_from_slice(&ts.to_be_bytes());
+        f.extend_from_slice(line.as_bytes());
+        f
+    }
+
+    fn write_segment(
+        dir: &path::PathBuf,
+        store: &mut MemStore,
+        partition: &str,
+        id: u64,
+        entries: &[(u64, &str)],
+    ) {
+        let mut writer = SegmentWriter::new(CompressionType::None, 1 << 20);
+        let mut terms: BTreeMap<String, Vec<u64>> = BTreeMap::new();
+        let mut all = Vec::new();
+        let mut off = 0u64;
+        for (ts, line) in entries {
+            let f = frame(*ts, line);
+            writer.append(&f);
+            terms
+                .entry(line.to_string())
+                .or_insert_with(Vec::new)
+                .push(off);
+            all.push(off);
+            off += f.len() as u64;
+        }
+        std::fs::write(dir.join(format!("{}.segment", id)), writer.finish()).unwrap();
+        let mut builder = SetBuilder::memory();
+        for (term, offs) in &terms {
+            builder.insert(term).unwrap();
+            let key = format!("{}_{}_{}_{}", SEGMENT_PREFIX, partition, id, term);
+            store
+                .map
+                .insert(key.into_bytes(), posting_list::encode_posting_list(offs));
+        }
+        std::fs::write(
+            dir.join(format!("segment_index_{}.fst", id)),
+            builder.into_inner().unwrap(),
+        )
+        .unwrap();
+        let all_key = format!("{}_{}_{}_{}", SEGMENT_PREFIX, partition, id, POSTING_LIST_ALL);
+        store
+            .map
+            .insert(all_key.into_bytes(), posting_list::encode_posting_list(&all));
+    }
+
+    fn lines<S: Store>(mut it: SegmentIterator<S>) -> Vec<Vec<u8>> {
+        let mut out = Vec::new();
+        while let Some(e) = it.entry() {
+            out.push(e.line.clone());
+            if it.next().is_none() {
+                break;
+            }
+        }
+        out.sort();
+        out
+    }
+
+    #[test]
+    fn query_mode_parsing() {
+        assert_eq!(
+            QueryMode::from_query("=exact"),
+            QueryMode::Exact("exact".to_string())
+        );
+        assert_eq!(
+            QueryMode::from_query("pre*"),
+            QueryMode::Prefix("pre".to_string())
+        );
+        assert_eq!(
+            QueryMode::from_query("/ab.*/"),
+            QueryMode::Regex("ab.*".to_string())
+        );
+        assert_eq!(
+            QueryMode::from_query("word"),
+            QueryMode::Fuzzy {
+                term: "word".to_string(),
+                distance: DEFAULT_FUZZY_DISTANCE,
+            }
+        );
+    }
+
+    #[test]
+    fn prefix_successor_increments_and_carries() {
+        assert_eq!(prefix_successor(b"ab"), Some(b"ac".to_vec()));
+        // trailing 0xff bytes are dropped before incrementing.
+        assert_eq!(prefix_successor(&[0x61, 0xff]), Some(vec![0x62]));
+        // all 0xff has no successor.
+        assert_eq!(prefix_successor(&[0xff, 0xff]), None);
+    }
+
+    // regression: a query matching zero entries must not underflow in `next()`.
+    #[test]
+    fn empty_segment_next_is_safe() {
+        let dir = tmpdir();
+        let mut store = MemStore::default();
+        write_segment(&dir, &mut store, "p", 0, &[]);
+        let mut it =
+            SegmentIterator::new(0, dir, store, String::new(), "p".to_string(), 0, 0).unwrap();
+        assert!(it.next().is_none());
+        assert!(it.entry().is_none());
+    }
+
+    // regression: an fst term with no posting list in the store must bail, not panic.
+    #[test]
+    fn missing_posting_list_bails() {
+        let dir = tmpdir();
+        let mut store = MemStore::default();
+        write_segment(&dir, &mut store, "p", 0, &[(1, "orphan")]);
+        // drop the term's posting list, leaving it only in the fst.
+        store
+            .map
+            .remove(format!("{}_{}_{}_{}", SEGMENT_PREFIX, "p", 0, "orphan").as_bytes());
+        let result = SegmentIterator::new(
+            0,
+            dir,
+            store,
+            "=orphan".to_string(),
+            "p".to_string(),
+            0,
+            0,
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn prefix_query_matches_range() {
+        let dir = tmpdir();
+        let mut store = MemStore::default();
+        write_segment(
+            &dir,
+            &mut store,
+            "p",
+            0,
+            &[(1, "alpha"), (2, "alpine"), (3, "beta")],
+        );
+        let it =
+            SegmentIterator::new(0, dir, store, "al*".to_string(), "p".to_string(), 0, 0).unwrap();
+        assert_eq!(lines(it), vec![b"alpha".to_vec(), b"alpine".to_vec()]);
+    }
+
+    #[test]
+    fn regex_query_matches() {
+        let dir = tmpdir();
+        let mut store = MemStore::default();
+        write_segment(
+            &dir,
+            &mut store,
+            "p",
+            0,
+            &[(1, "cat"), (2, "car"), (3, "dog")],
+        );
+        let it =
+            SegmentIterator::new(0, dir, store, "/ca./".to_string(), "p".to_string(), 0, 0).unwrap();
+        assert_eq!(lines(it), vec![b"car".to_vec(), b"cat".to_vec()]);
+    }
+}